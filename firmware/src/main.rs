@@ -5,11 +5,14 @@
 use panic_halt as _;
 
 use core::convert::Infallible;
+use core::mem::MaybeUninit;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::spi::{Mode, Phase, Polarity};
 use generic_array::typenum::{U5, U6};
-use hal::gpio::{gpioa, gpiob, Input, Output, PullUp, PushPull};
+use hal::gpio::{gpioa, gpiob, Alternate, Input, Output, PullUp, PushPull, AF0};
 use hal::prelude::*;
 use hal::serial;
+use hal::spi::{EightBit, Spi};
 use hal::usb;
 use hal::{stm32, timers};
 use keyberon::action::{k, l, m, Action, Action::*, HoldTapConfig};
@@ -17,17 +20,160 @@ use keyberon::debounce::Debouncer;
 use keyberon::impl_heterogenous_array;
 use keyberon::key_code::KbHidReport;
 use keyberon::key_code::KeyCode::*;
-use keyberon::layout::{Event, Layout};
+use keyberon::layout::{CustomEvent, Event, Layout};
 use keyberon::matrix::{Matrix, PressedKeys};
 use nb::block;
 use rtic::app;
+use smart_leds::{brightness, SmartLedsWrite, RGB8};
 use stm32f0xx_hal as hal;
+use ws2812_spi as ws2812;
 use usb_device::bus::UsbBusAllocator;
 use usb_device::class::UsbClass as _;
-use usb_device::device::UsbDeviceState;
+use usb_device::device::{UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usbd_serial::SerialPort;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 type UsbClass = keyberon::Class<'static, usb::UsbBusType, ()>;
 type UsbDevice = usb_device::device::UsbDevice<'static, usb::UsbBusType>;
+type Serial = SerialPort<'static, usb::UsbBusType>;
+
+/// Firmware version, reported on the `ver` console command and as the USB serial number.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Which half this is, published from `init` for the console's `flip?`.
+static IS_FLIPPED: AtomicBool = AtomicBool::new(false);
+/// Active layer, republished from `handle_event` for the console's `layer?`.
+static ACTIVE_LAYER: AtomicUsize = AtomicUsize::new(0);
+/// Set when running standalone on `LAYERS_SOLO`; cleared when the other half reappears.
+static SOLO: AtomicBool = AtomicBool::new(false);
+
+type BacklightSpi = Spi<
+    stm32::SPI1,
+    gpiob::PB3<Alternate<AF0>>,
+    gpiob::PB4<Alternate<AF0>>,
+    gpiob::PB5<Alternate<AF0>>,
+    EightBit,
+>;
+
+/// Board-level effects a key can trigger, dispatched from `handle_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomAction {
+    ResetToBootloader,
+    ToggleBacklight,
+    BacklightBrightnessUp,
+    BacklightBrightnessDown,
+}
+
+/// Commands for the low-priority `backlight` task, so a strip write never blocks `tick`.
+enum BacklightCmd {
+    Layer(usize),
+    Toggle,
+    BrightnessUp,
+    BrightnessDown,
+}
+
+/// Heartbeat cadence: emit one idle frame roughly every 50 ms at the 1 kHz tick.
+const HEARTBEAT_TICKS: u16 = 50;
+/// Declare the other half gone after ~500 ms without a valid frame.
+const LINK_TIMEOUT_TICKS: u16 = 500;
+/// Ticks to wait for a partner before falling back to solo; re-armed on link drop.
+const SOLO_DETECT_TICKS: u16 = 500;
+/// Remote-held keys released per tick after a link loss (< `handle_event`'s `capacity = 16`).
+const RELEASE_PER_TICK: usize = 4;
+
+/// Receive-side liveness for the inter-half link; aged from `tick` to release stuck remote keys.
+pub struct Link {
+    idle: u16,
+    connected: bool,
+    startup: u16,
+    held: [u16; 5],
+}
+
+impl Link {
+    const fn new() -> Self {
+        Link {
+            idle: 0,
+            connected: false,
+            startup: 0,
+            held: [0; 5],
+        }
+    }
+
+    /// Note that a frame just arrived from the other half.
+    fn mark_alive(&mut self) {
+        self.idle = 0;
+        self.startup = 0;
+        self.connected = true;
+    }
+
+    /// Remember whether a remote key is down so it can be released on link loss.
+    fn track(&mut self, e: Event) {
+        match e {
+            Event::Press(i, j) => {
+                if let Some(row) = self.held.get_mut(i as usize) {
+                    *row |= 1 << j;
+                }
+            }
+            Event::Release(i, j) => {
+                if let Some(row) = self.held.get_mut(i as usize) {
+                    *row &= !(1 << j);
+                }
+            }
+        }
+    }
+}
+
+/// Size of the debug console's outbound FIFO; one slot stays free, so it holds `LOG_BUF - 1` bytes.
+const LOG_BUF: usize = 256;
+
+/// SPSC byte ring for the CDC console; `handle_event` fills, the USB ISR drains. Drops newest when full.
+pub struct LogFifo {
+    buf: [u8; LOG_BUF],
+    head: usize,
+    tail: usize,
+}
+
+impl LogFifo {
+    const fn new() -> Self {
+        LogFifo {
+            buf: [0; LOG_BUF],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        let next = (self.head + 1) % LOG_BUF;
+        if next != self.tail {
+            self.buf[self.head] = byte;
+            self.head = next;
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.push(b);
+        }
+    }
+
+    /// Write `v` as an unpadded decimal number.
+    fn write_dec(&mut self, v: u8) {
+        let mut buf = [0u8; 3];
+        let n = fmt_dec(v, &mut buf);
+        self.write(&buf[..n]);
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            None
+        } else {
+            let byte = self.buf[self.tail];
+            self.tail = (self.tail + 1) % LOG_BUF;
+            Some(byte)
+        }
+    }
+}
 
 trait ResultExt<T> {
     fn get(self) -> T;
@@ -70,28 +216,28 @@ impl_heterogenous_array! {
     [0, 1, 2, 3, 4]
 }
 
-const L2_ENTER: Action = HoldTap {
+const L2_ENTER: Action<CustomAction> = HoldTap {
     timeout: 200,
     tap_hold_interval: 0,
     config: HoldTapConfig::HoldOnOtherKeyPress,
     hold: &l(2),
     tap: &k(Enter),
 };
-const CTRL_TAB: Action = HoldTap {
+const CTRL_TAB: Action<CustomAction> = HoldTap {
     timeout: 200,
     tap_hold_interval: 0,
     config: HoldTapConfig::Default,
     hold: &k(LCtrl),
     tap: &k(Tab),
 };
-const L1_SP: Action = HoldTap {
+const L1_SP: Action<CustomAction> = HoldTap {
     timeout: 200,
     tap_hold_interval: 0,
     config: HoldTapConfig::HoldOnOtherKeyPress,
     hold: &l(1),
     tap: &k(Space),
 };
-const SFT_BSP: Action = HoldTap {
+const SFT_BSP: Action<CustomAction> = HoldTap {
     timeout: 200,
     tap_hold_interval: 0,
     config: HoldTapConfig::Default,
@@ -110,13 +256,78 @@ macro_rules! c {
     };
 }
 
-const WORD_LEFT: Action = c!(Left);
-const WORD_RIGHT: Action = c!(Right);
-const PREV_TAB: Action = c!(PgUp);
-const NEXT_TAB: Action = c!(PgDown);
+const WORD_LEFT: Action<CustomAction> = c!(Left);
+const WORD_RIGHT: Action<CustomAction> = c!(Right);
+const PREV_TAB: Action<CustomAction> = c!(PgUp);
+const NEXT_TAB: Action<CustomAction> = c!(PgDown);
+
+/// Number of WS2812 LEDs wired to each half.
+const NUM_LEDS: usize = 6;
+/// Default underglow brightness, scaling every channel before it hits the wire.
+const BACKLIGHT_LEVEL: u8 = 32;
+
+/// Per-layer underglow colors, indexed by active layer. Keep in sync with `LAYERS`.
+static LAYER_COLORS: [RGB8; 5] = [
+    RGB8 { r: 0, g: 0, b: 0 },    // L0: alphas, underglow off
+    RGB8 { r: 0, g: 0, b: 48 },   // L1: brackets/navigation, blue
+    RGB8 { r: 48, g: 24, b: 0 },  // L2: symbols, amber
+    RGB8 { r: 0, g: 48, b: 0 },   // L3: function/number, green
+    RGB8 { r: 48, g: 0, b: 48 },  // L4: bare thumbs, magenta
+];
+
+/// Addressable underglow on SPI1 (~3 MHz), flushed once from `init` and then only from the low-priority `backlight` task.
+pub struct Backlight {
+    ws: ws2812::Ws2812<BacklightSpi>,
+    frame: [RGB8; NUM_LEDS],
+    level: u8,
+    layer: usize,
+    enabled: bool,
+}
+
+impl Backlight {
+    fn new(spi: BacklightSpi) -> Self {
+        let mut backlight = Backlight {
+            ws: ws2812::Ws2812::new(spi),
+            frame: [RGB8::default(); NUM_LEDS],
+            level: BACKLIGHT_LEVEL,
+            layer: 0,
+            enabled: true,
+        };
+        backlight.repaint(0);
+        backlight
+    }
+
+    /// Fill the framebuffer with the color for `layer` and flush it to the strip.
+    fn repaint(&mut self, layer: usize) {
+        self.layer = layer;
+        let color = if self.enabled {
+            LAYER_COLORS.get(layer).copied().unwrap_or_default()
+        } else {
+            RGB8::default()
+        };
+        for led in self.frame.iter_mut() {
+            *led = color;
+        }
+        self.ws
+            .write(brightness(self.frame.iter().cloned(), self.level))
+            .ok();
+    }
+
+    /// Toggle the underglow on or off, keeping the current layer color.
+    fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.repaint(self.layer);
+    }
+
+    /// Nudge the global brightness, saturating at the 0..=255 channel range.
+    fn brightness_delta(&mut self, delta: i16) {
+        self.level = (self.level as i16 + delta).clamp(0, 255) as u8;
+        self.repaint(self.layer);
+    }
+}
 
 #[rustfmt::skip]
-pub static LAYERS: keyberon::layout::Layers = &[
+pub static LAYERS: keyberon::layout::Layers<CustomAction> = &[
     &[
         // Layer 0: Alphas
         //-----L0----- , -----L1----- , -----L2----- , -----L3----- , -----L4----- , -----L5----- --SPLIT-- , -----R5----- , -----R4----- , -----R3----- , -----R2----- , -----R1----- , -----R0----- ,
@@ -150,34 +361,67 @@ pub static LAYERS: keyberon::layout::Layers = &[
         &[Trans        , Trans        , k(F9)        , k(F10)       , k(F11)       , k(F12)                 , k(No)        , k(Kb7)       , k(Kb8)       , k(Kb9)       , Trans        , Trans        ],
         &[Trans        , Trans        , Trans        , Trans        , Trans        , Trans                  , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
     ], &[
-        // Layer 4: Thumb keys without tap-hold
+        // Layer 4: Thumb keys without tap-hold, plus board controls
         //-----L0----- , -----L1----- , -----L2----- , -----L3----- , -----L4----- , -----L5-----           , -----R5----- , -----R4----- , -----R3----- , -----R2----- , -----R1----- , -----R0----- ,
         &[Trans        , Trans        , Trans        , Trans        , Trans        , Trans                  , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
-        &[Trans        , Trans        , Trans        , Trans        , Trans        , Trans                  , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
+        &[Trans        , Custom(CustomAction::ResetToBootloader), Custom(CustomAction::ToggleBacklight), Custom(CustomAction::BacklightBrightnessDown), Custom(CustomAction::BacklightBrightnessUp), Trans , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
         &[Trans        , Trans        , Trans        , Trans        , Trans        , Trans                  , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
         &[Trans        , Trans        , Trans        , Trans        , Trans        , Trans                  , Trans        , Trans        , Trans        , Trans        , Trans        , Trans        ],
         &[Trans        , Trans        , Trans        , k(Tab)       , k(Space)     , Trans                  , Trans        , k(Enter)     , k(BSpace)    , Trans        , Trans        , Trans        ],
     ],
 ];
 
+/// Standalone layout for a detached half: the 6 columns map straight through as a macropad.
+#[rustfmt::skip]
+pub static LAYERS_SOLO: keyberon::layout::Layers<CustomAction> = &[
+    &[
+        // Solo layer 0: digits, alphas and thumb mods
+        //--C0-- , --C1-- , --C2-- , --C3-- , --C4-- , --C5-- ,
+        &[k(Escape) , k(Kb1)   , k(Kb2)   , k(Kb3)   , k(Kb4)   , k(Kb5)   ],
+        &[k(Tab)    , k(Q)     , k(W)     , k(E)     , k(R)     , k(T)     ],
+        &[k(LCtrl)  , k(A)     , k(S)     , k(D)     , k(F)     , k(G)     ],
+        &[k(LShift) , k(Z)     , k(X)     , k(C)     , k(V)     , k(B)     ],
+        &[l(1)      , k(LGui)  , k(LAlt)  , k(Space) , k(Enter) , k(BSpace)],
+    ], &[
+        // Solo layer 1: navigation, function keys and board controls
+        //--C0-- , --C1-- , --C2-- , --C3-- , --C4-- , --C5-- ,
+        &[Trans , k(F1)    , k(F2)    , k(F3)    , k(F4)    , k(F5)    ],
+        &[Trans , Custom(CustomAction::ResetToBootloader), Custom(CustomAction::ToggleBacklight), Custom(CustomAction::BacklightBrightnessDown), Custom(CustomAction::BacklightBrightnessUp), Trans],
+        &[Trans , k(Left)  , k(Down)  , k(Up)    , k(Right) , k(No)    ],
+        &[Trans , k(Home)  , k(PgDown), k(PgUp)  , k(End)   , k(No)    ],
+        &[Trans , Trans    , Trans    , Trans    , Trans    , Trans    ],
+    ],
+];
+
 #[app(device = crate::hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
+        serial: Serial,
+        log: LogFifo,
         matrix: Matrix<Cols, Rows>,
         debouncer: Debouncer<PressedKeys<U5, U6>>,
-        layout: Layout,
+        layout: Layout<CustomAction>,
         timer: timers::Timer<stm32::TIM3>,
         transform: fn(Event) -> Event,
+        split_transform: fn(Event) -> Event,
+        solo_transform: fn(Event) -> Event,
         tx: serial::Tx<hal::pac::USART1>,
         rx: serial::Rx<hal::pac::USART1>,
+        backlight: Backlight,
+        link: Link,
     }
 
     #[init]
     fn init(mut c: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<UsbBusAllocator<usb::UsbBusType>> = None;
 
+        // Honor a pending DFU request before touching any peripherals.
+        if bootloader_requested() {
+            unsafe { jump_to_bootloader() };
+        }
+
         let mut rcc = c
             .device
             .RCC
@@ -204,7 +448,14 @@ const APP: () = {
         let usb_bus = USB_BUS.as_ref().unwrap();
 
         let usb_class = keyberon::new_class(usb_bus, ());
-        let usb_dev = keyberon::new_device(usb_bus);
+        let serial = SerialPort::new(usb_bus);
+        // Composite HID + CDC-ACM device; an IAD groups the console's two CDC interfaces.
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27db))
+            .manufacturer("RIIR Task Force")
+            .product("Keyberon")
+            .serial_number(VERSION)
+            .composite_with_iads()
+            .build();
 
         let mut timer = timers::Timer::tim3(c.device.TIM3, 1.khz(), &mut rcc);
         timer.listen(timers::Event::TimeOut);
@@ -213,11 +464,18 @@ const APP: () = {
         let is_flipped = cortex_m::interrupt::free(move |cs| pb6.into_pull_up_input(cs))
             .is_low()
             .get();
+        IS_FLIPPED.store(is_flipped, Ordering::Relaxed);
         let transform: fn(Event) -> Event = if is_flipped {
             |e| e.transform(|i, j| (i, 11 - j))
         } else {
             |e| e
         };
+        // Solo mapping: the mirror-wired right half reverses its 6 columns, the left maps straight.
+        let solo_transform: fn(Event) -> Event = if is_flipped {
+            |e| e.transform(|i, j| (i, 5 - j))
+        } else {
+            |e| e
+        };
 
         let pb9 = gpiob.pb9;
         let mut status_led = cortex_m::interrupt::free(move |cs| pb9.into_push_pull_output(cs));
@@ -236,6 +494,26 @@ const APP: () = {
         serial.listen(serial::Event::Rxne);
         let (tx, rx) = serial.split();
 
+        let (pb3, pb4, pb5) = (gpiob.pb3, gpiob.pb4, gpiob.pb5);
+        let spi_pins = cortex_m::interrupt::free(move |cs| {
+            (
+                pb3.into_alternate_af0(cs),
+                pb4.into_alternate_af0(cs),
+                pb5.into_alternate_af0(cs),
+            )
+        });
+        let spi = Spi::spi1(
+            c.device.SPI1,
+            spi_pins,
+            Mode {
+                polarity: Polarity::IdleLow,
+                phase: Phase::CaptureOnFirstTransition,
+            },
+            3.mhz(),
+            &mut rcc,
+        );
+        let backlight = Backlight::new(spi);
+
         let pa0 = gpioa.pa0;
         let pa1 = gpioa.pa1;
         let pa2 = gpioa.pa2;
@@ -270,47 +548,150 @@ const APP: () = {
         init::LateResources {
             usb_dev,
             usb_class,
+            serial,
+            log: LogFifo::new(),
             timer,
             debouncer: Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5),
             matrix: matrix.get(),
             layout: Layout::new(LAYERS),
             transform,
+            split_transform: transform,
+            solo_transform,
             tx,
             rx,
+            backlight,
+            link: Link::new(),
         }
     }
 
-    #[task(binds = USART1, priority = 5, spawn = [handle_event], resources = [rx])]
+    #[task(binds = USART1, priority = 5, spawn = [handle_event], resources = [rx, link])]
     fn rx(c: rx::Context) {
-        static mut BUF: [u8; 4] = [0; 4];
+        static mut BUF: [u8; 8] = [0; 8];
+        static mut IDX: usize = 0;
 
         if let Ok(b) = c.resources.rx.read() {
-            BUF.rotate_left(1);
-            BUF[3] = b;
-
-            if BUF[3] == b'\n' {
-                if let Ok(event) = de(&BUF[..]) {
-                    c.spawn.handle_event(Some(event)).unwrap();
+            if b == 0 {
+                // Frame boundary: decode, check the CRC, and only then act.
+                let mut payload = [0u8; 8];
+                if let Ok(len) = cobs_decode(&BUF[..*IDX], &mut payload) {
+                    if let Ok(frame) = de(&payload[..len]) {
+                        c.resources.link.mark_alive();
+                        if let Frame::Key(event) = frame {
+                            c.resources.link.track(event);
+                            c.spawn.handle_event(Some(event)).unwrap();
+                        }
+                    }
                 }
+                *IDX = 0;
+            } else if *IDX < BUF.len() {
+                BUF[*IDX] = b;
+                *IDX += 1;
+            } else {
+                // Overrun without a delimiter: drop and resync on the next 0x00.
+                *IDX = 0;
             }
         }
     }
 
-    #[task(binds = USB, priority = 4, resources = [usb_dev, usb_class])]
+    #[task(binds = USB, priority = 4, resources = [usb_dev, usb_class, serial, log])]
     fn usb_rx(c: usb_rx::Context) {
-        if c.resources.usb_dev.poll(&mut [c.resources.usb_class]) {
-            c.resources.usb_class.poll();
+        static mut LINE: [u8; 16] = [0; 16];
+        static mut LEN: usize = 0;
+
+        let usb_dev = c.resources.usb_dev;
+        let usb_class = c.resources.usb_class;
+        let serial = c.resources.serial;
+        let log = c.resources.log;
+
+        if usb_dev.poll(&mut [usb_class, serial]) {
+            usb_class.poll();
+
+            // Accept line commands from the console without blocking HID.
+            let mut rx = [0u8; 16];
+            if let Ok(n) = serial.read(&mut rx) {
+                for &b in &rx[..n] {
+                    match b {
+                        b'\r' | b'\n' => {
+                            run_command(&LINE[..*LEN], serial);
+                            *LEN = 0;
+                        }
+                        _ if *LEN < LINE.len() => {
+                            LINE[*LEN] = b;
+                            *LEN += 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Drain queued log bytes onto the CDC endpoint; only what the endpoint
+        // accepts is dequeued, the rest goes next interrupt.
+        let mut out = [0u8; 32];
+        let mut n = 0;
+        let mut idx = log.tail;
+        while n < out.len() && idx != log.head {
+            out[n] = log.buf[idx];
+            idx = (idx + 1) % LOG_BUF;
+            n += 1;
+        }
+        if n > 0 {
+            if let Ok(written) = serial.write(&out[..n]) {
+                log.tail = (log.tail + written) % LOG_BUF;
+            }
         }
     }
 
-    #[task(priority = 3, capacity = 8, resources = [usb_dev, usb_class, layout])]
+    #[task(priority = 3, capacity = 16, spawn = [backlight], resources = [usb_dev, usb_class, layout, log])]
     fn handle_event(mut c: handle_event::Context, event: Option<Event>) {
+        static mut CUR_LAYER: usize = 0;
+        static mut CUR_SOLO: bool = false;
+
         let report: KbHidReport = match event {
             None => {
-                c.resources.layout.tick();
-                c.resources.layout.keycodes().collect()
+                // Swap the layer set when crossing between split and solo.
+                let solo = SOLO.load(Ordering::Relaxed);
+                if solo != *CUR_SOLO {
+                    *CUR_SOLO = solo;
+                    *c.resources.layout = Layout::new(if solo { LAYERS_SOLO } else { LAYERS });
+                    *CUR_LAYER = 0;
+                    ACTIVE_LAYER.store(0, Ordering::Relaxed);
+                    c.spawn.backlight(BacklightCmd::Layer(0)).ok();
+                    c.resources.log.lock(|log| {
+                        log.write(if solo { b"mode solo\r\n" } else { b"mode split\r\n" });
+                    });
+                }
+                match c.resources.layout.tick() {
+                    CustomEvent::Press(&CustomAction::ResetToBootloader) => {
+                        reset_to_bootloader()
+                    }
+                    CustomEvent::Press(&CustomAction::ToggleBacklight) => {
+                        c.spawn.backlight(BacklightCmd::Toggle).ok();
+                    }
+                    CustomEvent::Press(&CustomAction::BacklightBrightnessUp) => {
+                        c.spawn.backlight(BacklightCmd::BrightnessUp).ok();
+                    }
+                    CustomEvent::Press(&CustomAction::BacklightBrightnessDown) => {
+                        c.spawn.backlight(BacklightCmd::BrightnessDown).ok();
+                    }
+                    _ => {}
+                }
+                let report = c.resources.layout.keycodes().collect();
+                let layer = c.resources.layout.current_layer();
+                if layer != *CUR_LAYER {
+                    *CUR_LAYER = layer;
+                    ACTIVE_LAYER.store(layer, Ordering::Relaxed);
+                    c.spawn.backlight(BacklightCmd::Layer(layer)).ok();
+                    c.resources.log.lock(|log| {
+                        log.write(b"layer ");
+                        log.write_dec(layer as u8);
+                        log.write(b"\r\n");
+                    });
+                }
+                report
             }
             Some(e) => {
+                c.resources.log.lock(|log| log_event(log, e));
                 c.resources.layout.event(e);
                 return;
             }
@@ -331,41 +712,344 @@ const APP: () = {
     #[task(
         binds = TIM3,
         priority = 2,
-        spawn = [handle_event],
-        resources = [matrix, debouncer, timer, &transform, tx],
+        spawn = [handle_event, link_tx],
+        resources = [matrix, debouncer, timer, transform, split_transform, solo_transform, link],
     )]
-    fn tick(c: tick::Context) {
+    fn tick(mut c: tick::Context) {
+        static mut IDLE_TX: u16 = 0;
+
         c.resources.timer.wait().ok();
 
+        // Age the link and pick the solo/split mapping before reading the matrix,
+        // releasing any keys the absent half was holding.
+        let spawn = c.spawn;
+        let solo = c.resources.link.lock(|link| {
+            if link.connected {
+                link.idle = link.idle.saturating_add(1);
+                if link.idle >= LINK_TIMEOUT_TICKS {
+                    link.connected = false;
+                }
+            } else {
+                link.startup = link.startup.saturating_add(1);
+            }
+            // Release held remote keys a few per tick; a bit is cleared only once
+            // its Release is accepted, so a full queue backs off and retries.
+            if !link.connected {
+                let mut budget = RELEASE_PER_TICK;
+                'drain: for (i, bits_ref) in link.held.iter_mut().enumerate() {
+                    while *bits_ref != 0 {
+                        if budget == 0 {
+                            break 'drain;
+                        }
+                        let j = bits_ref.trailing_zeros();
+                        if spawn
+                            .handle_event(Some(Event::Release(i as u8, j as u8)))
+                            .is_err()
+                        {
+                            break 'drain;
+                        }
+                        *bits_ref &= *bits_ref - 1;
+                        budget -= 1;
+                    }
+                }
+            }
+            !link.connected && link.startup >= SOLO_DETECT_TICKS
+        });
+        SOLO.store(solo, Ordering::Relaxed);
+
+        // Solo mapping when standalone, the per-half flip otherwise.
+        *c.resources.transform = if solo {
+            *c.resources.solo_transform
+        } else {
+            *c.resources.split_transform
+        };
+
+        let mut activity = false;
         for event in c
             .resources
             .debouncer
             .events(c.resources.matrix.get().get())
-            .map(c.resources.transform)
+            .map(*c.resources.transform)
         {
-            for &b in &ser(event) {
-                block!(c.resources.tx.write(b)).get();
+            activity = true;
+            let mut buf = [0u8; 8];
+            let n = ser(event, &mut buf);
+            spawn.link_tx((buf, n)).ok();
+            spawn.handle_event(Some(event)).unwrap();
+        }
+
+        // Heartbeat while idle so the other half sees the cable is still live.
+        if activity {
+            *IDLE_TX = 0;
+        } else {
+            *IDLE_TX += 1;
+            if *IDLE_TX >= HEARTBEAT_TICKS {
+                *IDLE_TX = 0;
+                let mut buf = [0u8; 8];
+                let n = heartbeat(&mut buf);
+                spawn.link_tx((buf, n)).ok();
             }
-            c.spawn.handle_event(Some(event)).unwrap();
         }
-        c.spawn.handle_event(None).unwrap();
+
+        spawn.handle_event(None).unwrap();
+    }
+
+    /// Push inter-half frames onto USART1 off the `tick` hot path, at lowest priority.
+    #[task(priority = 1, capacity = 16, resources = [tx])]
+    fn link_tx(c: link_tx::Context, frame: ([u8; 8], usize)) {
+        let (buf, n) = frame;
+        for &b in &buf[..n] {
+            block!(c.resources.tx.write(b)).get();
+        }
+    }
+
+    #[task(priority = 1, resources = [backlight])]
+    fn backlight(c: backlight::Context, cmd: BacklightCmd) {
+        let backlight = c.resources.backlight;
+        match cmd {
+            BacklightCmd::Layer(layer) => backlight.repaint(layer),
+            BacklightCmd::Toggle => backlight.toggle(),
+            BacklightCmd::BrightnessUp => backlight.brightness_delta(16),
+            BacklightCmd::BrightnessDown => backlight.brightness_delta(-16),
+        }
     }
 
     extern "C" {
         fn CEC_CAN();
+        fn ADC_COMP();
     }
 };
 
-fn de(bytes: &[u8]) -> Result<Event, ()> {
-    match *bytes {
-        [b'P', i, j, b'\n'] => Ok(Event::Press(i, j)),
-        [b'R', i, j, b'\n'] => Ok(Event::Release(i, j)),
-        _ => Err(()),
+/// Magic token handed from `reset_to_bootloader` to `init` across a reset.
+const BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+/// Base of the STM32F0 system-memory (ROM) bootloader vector table.
+const SYSTEM_MEMORY: u32 = 0x1FFF_C800;
+
+/// DFU request retained across a reset; needs a `cortex-m-rt` that keeps `.uninit.*` (>= 0.6.7).
+/// Stored `[magic, !magic]` so an unpreserved word degrades to a plain reboot.
+#[link_section = ".uninit.BOOTLOADER"]
+static mut BOOTLOADER_FLAG: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+/// Whether the retained flag holds a well-formed DFU request.
+fn bootloader_requested() -> bool {
+    // volatile: on a cold boot this word may never have been written.
+    let [magic, check] = unsafe { core::ptr::read_volatile(BOOTLOADER_FLAG.as_ptr()) };
+    magic == BOOTLOADER_MAGIC && check == !BOOTLOADER_MAGIC
+}
+
+/// Stamp the magic token and reset; `init` performs the jump on the way back up.
+fn reset_to_bootloader() -> ! {
+    unsafe { BOOTLOADER_FLAG.as_mut_ptr().write([BOOTLOADER_MAGIC, !BOOTLOADER_MAGIC]) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Clear the flag and jump into the ROM bootloader for DFU reflash.
+unsafe fn jump_to_bootloader() -> ! {
+    BOOTLOADER_FLAG.as_mut_ptr().write([0, 0]);
+    // Cortex-M0 has no VTOR, so remap system memory to 0x0000_0000 (MEM_MODE =
+    // 0b01) before the branch or the ROM USB interrupt vectors into app flash.
+    let rcc = &*stm32::RCC::ptr();
+    rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+    // Read back so the clock is live before SYSCFG is written.
+    let _ = rcc.apb2enr.read();
+    let syscfg = &*stm32::SYSCFG::ptr();
+    syscfg.cfgr1.modify(|_, w| w.mem_mode().bits(0b01));
+    // Make the remap take effect before any vector fetch sees the new mapping.
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    let sp = core::ptr::read_volatile(SYSTEM_MEMORY as *const u32);
+    let entry = core::ptr::read_volatile((SYSTEM_MEMORY + 4) as *const u32);
+    // Set MSP, unmask interrupts, then branch in one block so `entry` survives
+    // the stack switch and interrupts go live only once MSP is correct.
+    core::arch::asm!(
+        "msr msp, {sp}",
+        "isb",
+        "cpsie i",
+        "bx {entry}",
+        sp = in(reg) sp,
+        entry = in(reg) entry,
+        options(noreturn),
+    );
+}
+
+/// Render `v` as unpadded decimal into `out`, returning the digit count.
+fn fmt_dec(mut v: u8, out: &mut [u8]) -> usize {
+    let mut n = 0;
+    let hundreds = v >= 100;
+    if hundreds {
+        out[n] = b'0' + v / 100;
+        n += 1;
+        v %= 100;
+    }
+    if hundreds || v >= 10 {
+        out[n] = b'0' + v / 10;
+        n += 1;
+        v %= 10;
     }
+    out[n] = b'0' + v;
+    n + 1
 }
-fn ser(e: Event) -> [u8; 4] {
-    match e {
-        Event::Press(i, j) => [b'P', i, j, b'\n'],
-        Event::Release(i, j) => [b'R', i, j, b'\n'],
+
+/// Append a human-readable `<P|R> <row>,<col>` line for `e` to the console FIFO.
+fn log_event(log: &mut LogFifo, e: Event) {
+    let (tag, i, j) = match e {
+        Event::Press(i, j) => (b'P', i, j),
+        Event::Release(i, j) => (b'R', i, j),
+    };
+    log.push(tag);
+    log.push(b' ');
+    log.write_dec(i);
+    log.push(b',');
+    log.write_dec(j);
+    log.push(b'\r');
+    log.push(b'\n');
+}
+
+/// Answer a console line command on `serial`. Unknown commands are ignored.
+fn run_command(line: &[u8], serial: &mut Serial) {
+    match line {
+        b"layer?" => {
+            let layer = ACTIVE_LAYER.load(Ordering::Relaxed);
+            let mut buf = [0u8; 3];
+            let n = fmt_dec(layer as u8, &mut buf);
+            serial.write(b"layer ").ok();
+            serial.write(&buf[..n]).ok();
+            serial.write(b"\r\n").ok();
+        }
+        b"flip?" => {
+            let side: &[u8] = if IS_FLIPPED.load(Ordering::Relaxed) {
+                b"right\r\n"
+            } else {
+                b"left\r\n"
+            };
+            serial.write(side).ok();
+        }
+        b"mode?" => {
+            let mode: &[u8] = if SOLO.load(Ordering::Relaxed) {
+                b"solo\r\n"
+            } else {
+                b"split\r\n"
+            };
+            serial.write(mode).ok();
+        }
+        b"ver" => {
+            serial.write(VERSION.as_bytes()).ok();
+            serial.write(b"\r\n").ok();
+        }
+        _ => {}
+    }
+}
+
+/// A decoded inter-half frame: either a key event or a bare liveness heartbeat.
+enum Frame {
+    Key(Event),
+    Heartbeat,
+}
+
+/// Link-layer frame tags, carried as the first payload byte.
+const TAG_PRESS: u8 = b'P';
+const TAG_RELEASE: u8 = b'R';
+const TAG_HEARTBEAT: u8 = b'H';
+
+/// CRC-8 (polynomial 0x07) over `data`, guarding the `[tag, i, j]` payload.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// COBS-encode `data` into `out` (>= `data.len() + 2` bytes), returning the encoded length.
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    let mut code_idx = 0;
+    let mut write_idx = 1;
+    let mut code = 1u8;
+    for &b in data {
+        if b == 0 {
+            out[code_idx] = code;
+            code = 1;
+            code_idx = write_idx;
+            write_idx += 1;
+        } else {
+            out[write_idx] = b;
+            write_idx += 1;
+            code += 1;
+            if code == 0xFF {
+                out[code_idx] = code;
+                code = 1;
+                code_idx = write_idx;
+                write_idx += 1;
+            }
+        }
+    }
+    out[code_idx] = code;
+    write_idx
+}
+
+/// COBS-decode one frame (delimiter stripped) into `out`; `Err` on malformed encoding.
+fn cobs_decode(data: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < data.len() {
+        let code = data[read];
+        if code == 0 {
+            return Err(());
+        }
+        read += 1;
+        for _ in 1..code {
+            *out.get_mut(write).ok_or(())? = *data.get(read).ok_or(())?;
+            write += 1;
+            read += 1;
+        }
+        if code < 0xFF && read < data.len() {
+            *out.get_mut(write).ok_or(())? = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/// Build a delimited, CRC-protected COBS frame for `payload` in `out` (>= 8 bytes); returns its length.
+fn encode(payload: [u8; 3], out: &mut [u8]) -> usize {
+    let mut framed = [0u8; 4];
+    framed[..3].copy_from_slice(&payload);
+    framed[3] = crc8(&payload);
+    let n = cobs_encode(&framed, out);
+    out[n] = 0; // delimiter
+    n + 1
+}
+
+/// Serialize a key `Event` into a wire frame in `out`, returning its length.
+fn ser(e: Event, out: &mut [u8]) -> usize {
+    let payload = match e {
+        Event::Press(i, j) => [TAG_PRESS, i, j],
+        Event::Release(i, j) => [TAG_RELEASE, i, j],
+    };
+    encode(payload, out)
+}
+
+/// Serialize a heartbeat frame into `out`, returning its length.
+fn heartbeat(out: &mut [u8]) -> usize {
+    encode([TAG_HEARTBEAT, 0, 0], out)
+}
+
+/// Validate a decoded `[tag, i, j, crc]` payload and turn it into a `Frame`.
+fn de(payload: &[u8]) -> Result<Frame, ()> {
+    if payload.len() != 4 || crc8(&payload[..3]) != payload[3] {
+        return Err(());
+    }
+    match payload[0] {
+        TAG_PRESS => Ok(Frame::Key(Event::Press(payload[1], payload[2]))),
+        TAG_RELEASE => Ok(Frame::Key(Event::Release(payload[1], payload[2]))),
+        TAG_HEARTBEAT => Ok(Frame::Heartbeat),
+        _ => Err(()),
     }
 }